@@ -0,0 +1,86 @@
+use std::io;
+
+/// Codec applied to values before they hit disk. Chosen per column (or
+/// globally, by using the same variant for every column) when calling
+/// [`crate::InFile::open_with_compression`].
+///
+/// Mirrors the per-column-family codecs RocksDB exposes (Snappy/Zlib/Lz4/
+/// Zstd); only the codecs below are implemented so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+impl Compression {
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+            Compression::Snappy => 3,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> io::Result<Compression> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            3 => Ok(Compression::Snappy),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression tag {}", tag),
+            )),
+        }
+    }
+}
+
+/// Compresses `value` with `compression` and frames it as it will be
+/// written to disk: 1-byte codec tag, 8-byte original length (little
+/// endian), then the compressed payload. The tag and length let `decode`
+/// recover the plaintext without the caller having to remember which
+/// codec a given column used.
+pub fn encode(compression: Compression, value: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = match compression {
+        Compression::None => value.to_vec(),
+        Compression::Lz4 => lz4_flex::block::compress(value),
+        Compression::Zstd => zstd::bulk::compress(value, 0)?,
+        Compression::Snappy => snap::raw::Encoder::new()
+            .compress_vec(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+    };
+    let mut out = Vec::with_capacity(payload.len() + 9);
+    out.push(compression.tag());
+    out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+/// Reverses [`encode`], reading the codec tag and original length off the
+/// front of `data` before decompressing the remainder.
+pub fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 9 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "value is too short to contain a compression header",
+        ));
+    }
+    let compression = Compression::from_tag(data[0])?;
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&data[1..9]);
+    let original_len = u64::from_le_bytes(len_bytes) as usize;
+    let payload = &data[9..];
+    match compression {
+        Compression::None => Ok(payload.to_vec()),
+        Compression::Lz4 => lz4_flex::block::decompress(payload, original_len)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Compression::Zstd => zstd::bulk::decompress(payload, original_len),
+        Compression::Snappy => snap::raw::Decoder::new()
+            .decompress_vec(payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
@@ -0,0 +1,104 @@
+use std::io;
+use std::path::PathBuf;
+
+/// How keys within a column are mapped onto file paths.
+///
+/// Exposed in [`crate::InFile::open_with_layout`] so an existing flat-hex
+/// database keeps loading the way it always has; new databases can opt into
+/// [`Layout::Sharded`] to avoid the filename-length and directory-size
+/// limits that a key-per-file layout runs into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// One file per key, named `0x<hex key>` directly under the column
+    /// directory. Simple, but a key longer than ~127 bytes overflows the
+    /// 255-byte filename limit on ext4/APFS, and a column with millions of
+    /// keys becomes a single directory many filesystems handle poorly.
+    #[default]
+    Flat,
+    /// Keys are hashed with a fast non-cryptographic hash, then bucketed
+    /// two levels deep by the hash's leading bytes:
+    /// `<col>/<hash[0]>/<hash[1]>/<hex(hash)>`. Filenames and directory
+    /// fan-out stay bounded regardless of key size or count. Two keys can
+    /// hash to the same file, so each file stores a chain of `(key,
+    /// value)` records rather than a single value; see [`encode_records`]
+    /// and [`decode_records`].
+    Sharded,
+}
+
+impl Layout {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Layout::Flat => "flat",
+            Layout::Sharded => "sharded",
+        }
+    }
+
+    pub(crate) fn from_str(s: &str) -> Option<Layout> {
+        match s {
+            "flat" => Some(Layout::Flat),
+            "sharded" => Some(Layout::Sharded),
+            _ => None,
+        }
+    }
+}
+
+/// Hashes `key` with xxh3 for use as a shard address. Not cryptographic —
+/// collisions are expected and handled by chaining records within a file,
+/// not avoided.
+pub fn hash_key(key: &[u8]) -> [u8; 8] {
+    twox_hash::xxh3::hash64(key).to_be_bytes()
+}
+
+/// Returns the two-level shard path (relative to the column directory) for
+/// a key's hash: `<hash[0]>/<hash[1]>/<hex(hash)>`.
+pub fn shard_path(hash: &[u8; 8]) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(hex::encode(&hash[0..1]));
+    path.push(hex::encode(&hash[1..2]));
+    path.push(hex::encode(hash));
+    path
+}
+
+/// Serializes a shard file's full record chain: each record is `key_len`
+/// (u32 LE) + `key` + `value_len` (u64 LE) + `value`, back to back. `value`
+/// here is whatever [`crate::compression::encode`] produced, so shard
+/// files compose with per-column compression unchanged.
+pub fn encode_records(records: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in records {
+        out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        out.extend_from_slice(key);
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+    out
+}
+
+/// Reverses [`encode_records`].
+pub fn decode_records(data: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let too_short = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated shard record");
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let key_len = u32::from_le_bytes(
+            data.get(offset..offset + 4)
+                .ok_or_else(too_short)?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        offset += 4;
+        let key = data.get(offset..offset + key_len).ok_or_else(too_short)?;
+        offset += key_len;
+        let value_len = u64::from_le_bytes(
+            data.get(offset..offset + 8)
+                .ok_or_else(too_short)?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        offset += 8;
+        let value = data.get(offset..offset + value_len).ok_or_else(too_short)?;
+        offset += value_len;
+        records.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(records)
+}
@@ -0,0 +1,150 @@
+use crate::{Compression, Layout};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The schema a database was created with: its column count, key
+/// [`Layout`], and per-column [`Compression`]. Persisted to `<path>/meta`
+/// the first time a database is opened so a later `open*` call with
+/// different arguments fails loudly instead of silently reading (or
+/// writing) the db as if it had a different shape.
+#[derive(Debug, PartialEq, Eq)]
+struct Meta {
+    num_cols: u32,
+    layout: Layout,
+    compression: Vec<Compression>,
+}
+
+impl Meta {
+    fn serialize(&self) -> String {
+        let compression = self
+            .compression
+            .iter()
+            .map(|c| c.tag().to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "num_cols={}\nlayout={}\ncompression={}\n",
+            self.num_cols,
+            self.layout.as_str(),
+            compression
+        )
+    }
+
+    fn parse(contents: &str) -> io::Result<Meta> {
+        let invalid = |why: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed meta file: {}", why),
+            )
+        };
+        let mut num_cols = None;
+        let mut layout = None;
+        let mut compression = None;
+        for line in contents.lines() {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected key=value"))?;
+            match key {
+                "num_cols" => {
+                    num_cols = Some(
+                        value
+                            .parse::<u32>()
+                            .map_err(|_| invalid("num_cols is not a number"))?,
+                    )
+                }
+                "layout" => {
+                    layout =
+                        Some(Layout::from_str(value).ok_or_else(|| invalid("unknown layout"))?)
+                }
+                "compression" => {
+                    compression = Some(
+                        value
+                            .split(',')
+                            .filter(|tag| !tag.is_empty())
+                            .map(|tag| {
+                                tag.parse::<u8>()
+                                    .map_err(|_| invalid("compression tag is not a number"))
+                                    .and_then(Compression::from_tag)
+                            })
+                            .collect::<io::Result<Vec<_>>>()?,
+                    )
+                }
+                _ => {}
+            }
+        }
+        Ok(Meta {
+            num_cols: num_cols.ok_or_else(|| invalid("missing num_cols"))?,
+            layout: layout.ok_or_else(|| invalid("missing layout"))?,
+            compression: compression.ok_or_else(|| invalid("missing compression"))?,
+        })
+    }
+}
+
+fn meta_path(db_path: &Path) -> PathBuf {
+    let mut path = PathBuf::from(db_path);
+    path.push("meta");
+    path
+}
+
+/// Loads the meta file at `db_path`, if any, and checks it against the
+/// schema the caller is opening with, then persists the (possibly updated)
+/// schema. Called on every `open*`, after `migration::reconcile_columns`
+/// has already reconciled the on-disk column directories against
+/// `num_cols` — so a `num_cols` change here is the expected result of that
+/// migration, not a mismatch to reject; only `layout` (which nothing
+/// migrates) and the `compression` of columns that existed before this
+/// call are checked.
+pub fn reconcile(
+    db_path: &Path,
+    num_cols: u32,
+    layout: Layout,
+    compression: &[Compression],
+) -> io::Result<()> {
+    let path = meta_path(db_path);
+    let wanted = Meta {
+        num_cols,
+        layout,
+        compression: compression.to_vec(),
+    };
+    if path.is_file() {
+        let existing = Meta::parse(&fs::read_to_string(&path)?)?;
+        let common = existing.num_cols.min(wanted.num_cols) as usize;
+        if existing.layout != wanted.layout || existing.compression[..common] != wanted.compression[..common]
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "schema mismatch at {:?}: db was created with num_cols={}, layout={:?}, \
+                     compression={:?}, but open was called with num_cols={}, layout={:?}, \
+                     compression={:?}",
+                    db_path,
+                    existing.num_cols,
+                    existing.layout,
+                    existing.compression,
+                    wanted.num_cols,
+                    wanted.layout,
+                    wanted.compression,
+                ),
+            ));
+        }
+    }
+    fs::write(path, wanted.serialize())
+}
+
+/// Unconditionally overwrites the meta file, for a caller (`add_column`,
+/// `remove_column`) that is intentionally changing the schema rather than
+/// just opening against the existing one.
+pub fn persist(
+    db_path: &Path,
+    num_cols: u32,
+    layout: Layout,
+    compression: &[Compression],
+) -> io::Result<()> {
+    let meta = Meta {
+        num_cols,
+        layout,
+        compression: compression.to_vec(),
+    };
+    fs::write(meta_path(db_path), meta.serialize())
+}
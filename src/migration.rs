@@ -0,0 +1,62 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Policy for what [`crate::InFile::open_with_migration`] does with an
+/// on-disk column directory that falls outside the requested `num_cols`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OnColumnRemoved {
+    /// Fail to open rather than silently ignore data that's no longer
+    /// part of the requested schema. The safe default.
+    #[default]
+    Refuse,
+    /// Move the column's directory into `<path>/removed/<col>` so the
+    /// data isn't deleted, just set aside.
+    Trash,
+}
+
+/// Reconciles the column directories actually present under `db_path`
+/// against the requested `num_cols`: creates any `0..num_cols` directory
+/// that doesn't exist yet, and applies `on_removed` to any present
+/// directory numbered `num_cols` or higher.
+pub fn reconcile_columns(db_path: &Path, num_cols: u32, on_removed: OnColumnRemoved) -> io::Result<()> {
+    for entry in fs::read_dir(db_path)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let col: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(col) => col,
+            Err(_) => continue,
+        };
+        if col < num_cols {
+            continue;
+        }
+        match on_removed {
+            OnColumnRemoved::Refuse => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "column {} exists on disk but is outside the requested num_cols={}; \
+                         pass OnColumnRemoved::Trash to open_with_migration to move it aside instead",
+                        col, num_cols
+                    ),
+                ));
+            }
+            OnColumnRemoved::Trash => {
+                let trash_dir = db_path.join("removed");
+                fs::create_dir_all(&trash_dir)?;
+                let dest = trash_dir.join(col.to_string());
+                if dest.exists() {
+                    fs::remove_dir_all(&dest)?;
+                }
+                fs::rename(&path, &dest)?;
+            }
+        }
+    }
+    for col in 0..num_cols {
+        fs::create_dir_all(db_path.join(col.to_string()))?;
+    }
+    Ok(())
+}
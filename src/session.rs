@@ -0,0 +1,64 @@
+use crate::InFile;
+use kvdb::{DBTransaction, DBValue, KeyValueDB};
+use std::collections::BTreeMap;
+use std::io;
+
+/// A buffered overlay of pending writes against an [`InFile`], staged in
+/// memory until [`commit`](Session::commit) folds them into a single
+/// crash-safe transaction, or discarded with [`rollback`](Session::rollback).
+/// Nothing under the session's base path is touched until `commit`.
+///
+/// Borrowed from the sessioned-store pattern used by the Findora storage
+/// layer: `get` checks the overlay first so a session sees its own writes
+/// before they've reached disk, then falls back to the base db.
+pub struct Session<'a> {
+    db: &'a InFile,
+    overlay: BTreeMap<(u32, Vec<u8>), Option<DBValue>>,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(db: &'a InFile) -> Session<'a> {
+        Session {
+            db,
+            overlay: BTreeMap::new(),
+        }
+    }
+
+    /// Reads a key, preferring this session's uncommitted overlay over the
+    /// base db so a session sees its own pending writes.
+    pub fn get(&self, col: u32, key: &[u8]) -> io::Result<Option<DBValue>> {
+        match self.overlay.get(&(col, key.to_vec())) {
+            Some(value) => Ok(value.clone()),
+            None => self.db.get(col, key),
+        }
+    }
+
+    /// Stages an insert; visible to this session's own `get` immediately,
+    /// but not written to disk until `commit`.
+    pub fn put(&mut self, col: u32, key: &[u8], value: DBValue) {
+        self.overlay.insert((col, key.to_vec()), Some(value));
+    }
+
+    /// Stages a delete; visible to this session's own `get` immediately,
+    /// but not applied to disk until `commit`.
+    pub fn delete(&mut self, col: u32, key: &[u8]) {
+        self.overlay.insert((col, key.to_vec()), None);
+    }
+
+    /// Folds every staged write into a single [`DBTransaction`] and routes
+    /// it through the base db's crash-safe `write`, so a session's changes
+    /// land on disk atomically or not at all.
+    pub fn commit(self) -> io::Result<()> {
+        let mut txn = DBTransaction::new();
+        for ((col, key), value) in self.overlay {
+            match value {
+                Some(value) => txn.put_vec(col, &key, value),
+                None => txn.delete(col, &key),
+            }
+        }
+        self.db.write(txn)
+    }
+
+    /// Discards every staged write without touching the base db.
+    pub fn rollback(self) {}
+}
@@ -0,0 +1,25 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Recursively hard-links every regular file under `src` into the same
+/// relative layout under `dst`, creating directories as needed. Hard
+/// linking (rather than copying) makes a snapshot cheap regardless of how
+/// much data a column holds, at the cost of the snapshot and the live
+/// column sharing disk blocks until one of them is rewritten — harmless
+/// here, since `InFile` never edits a file in place (writes always land
+/// through a fresh tmp file and a rename).
+pub fn hardlink_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if path.is_dir() {
+            hardlink_tree(&path, &dst_path)?;
+        } else {
+            fs::hard_link(&path, &dst_path)?;
+        }
+    }
+    Ok(())
+}